@@ -0,0 +1,720 @@
+extern crate image;
+extern crate libheif_rs;
+extern crate rawloader;
+
+use std::fmt;
+use std::fs;
+use std::f32;
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::ascii::AsciiExt;
+use image::GenericImage;
+use image::Pixel;
+
+// a decode route for a file extension: either a format the `image` crate
+// already understands natively, or one of the external decoders we shell
+// out to for formats it doesn't (HEIC/HEIF, camera RAW).
+pub enum DecodeFormat {
+    Native(image::ImageFormat),
+    Heic,
+    Raw,
+}
+
+// extension-based detection of filetype
+pub fn supported_extension(path: &Path) -> Option<DecodeFormat> {
+    match path.extension() {
+        None => { None },
+        Some(ext) => {
+            match ext.to_str().unwrap().to_ascii_lowercase().as_ref() {
+                "gif" => { Some(DecodeFormat::Native(image::ImageFormat::GIF)) },
+                "png" => { Some(DecodeFormat::Native(image::ImageFormat::PNG)) },
+                "png-large" => { Some(DecodeFormat::Native(image::ImageFormat::PNG)) },
+                "jpg" => { Some(DecodeFormat::Native(image::ImageFormat::JPEG)) },
+                "jpeg" => { Some(DecodeFormat::Native(image::ImageFormat::JPEG)) },
+                "jpe" => { Some(DecodeFormat::Native(image::ImageFormat::JPEG)) },
+                "jpg-large" => { Some(DecodeFormat::Native(image::ImageFormat::JPEG)) },
+                "webp" => { Some(DecodeFormat::Native(image::ImageFormat::WEBP)) },
+                "heic" => { Some(DecodeFormat::Heic) },
+                "heif" => { Some(DecodeFormat::Heic) },
+                "cr2" => { Some(DecodeFormat::Raw) },
+                "nef" => { Some(DecodeFormat::Raw) },
+                "arw" => { Some(DecodeFormat::Raw) },
+                "dng" => { Some(DecodeFormat::Raw) },
+                _ => { None },
+            }
+        }
+    }
+}
+
+// decodes a HEIC/HEIF file into an RGB DynamicImage via libheif
+pub fn decode_heic(path: &Path) -> Option<image::DynamicImage> {
+    let lib_heif = libheif_rs::LibHeif::new();
+    let path_str = match path.to_str() {
+        Some(path_str) => path_str,
+        None => { println!("[non-UTF-8 path, cannot decode] {}", path.display()); return None; }
+    };
+    let ctx = match libheif_rs::HeifContext::read_from_file(path_str) {
+        Ok(ctx) => ctx,
+        Err(err) => { println!("[{}] {}", err, path.display()); return None; }
+    };
+    let handle = match ctx.primary_image_handle() {
+        Ok(handle) => handle,
+        Err(err) => { println!("[{}] {}", err, path.display()); return None; }
+    };
+    let image = match lib_heif.decode(&handle, libheif_rs::ColorSpace::Rgb(libheif_rs::RgbChroma::Rgb), None) {
+        Ok(image) => image,
+        Err(err) => { println!("[{}] {}", err, path.display()); return None; }
+    };
+
+    let planes = image.planes();
+    let plane = match planes.interleaved {
+        Some(plane) => plane,
+        None => { println!("[no interleaved RGB plane] {}", path.display()); return None; }
+    };
+
+    // copy row by row in case the plane's stride pads beyond width * 3 bytes
+    let width = plane.width;
+    let height = plane.height;
+    let mut pixels = Vec::with_capacity((width * height * 3) as usize);
+    for row in 0..height {
+        let start = (row as usize) * plane.stride;
+        pixels.extend_from_slice(&plane.data[start..start + (width as usize) * 3]);
+    }
+
+    image::RgbImage::from_raw(width, height, pixels).map(image::DynamicImage::ImageRgb8)
+}
+
+// decodes a camera RAW file into a grayscale DynamicImage. We skip full
+// demosaicing since the perceptual hashes only need an approximate image,
+// but a raw CFA sample is still just one color-filtered photosite: it has
+// to be black/white-level normalized per its own color, and adjacent 2x2
+// CFA quads (one of each filter color) averaged into a luminance value,
+// or the "grayscale" handed to the hashers is really a color checkerboard.
+pub fn decode_raw(path: &Path) -> Option<image::DynamicImage> {
+    let raw_image = match rawloader::decode_file(path) {
+        Ok(raw_image) => raw_image,
+        Err(err) => { println!("[{}] {}", err, path.display()); return None; }
+    };
+
+    let width = raw_image.width;
+    let height = raw_image.height;
+
+    let samples: Vec<f32> = match raw_image.data {
+        rawloader::RawImageData::Integer(ref data) => data.iter().map(|&v| v as f32).collect(),
+        rawloader::RawImageData::Float(ref data) => data.clone(),
+    };
+
+    if samples.len() != width * height {
+        println!("[RAW sample count {} doesn't match {}x{}] {}", samples.len(), width, height, path.display());
+        return None;
+    }
+
+    // normalize each photosite against its own CFA color's black/white level
+    let blacklevels = raw_image.blacklevels;
+    let whitelevels = raw_image.whitelevels;
+    let mut normalized = vec![0.0f32; samples.len()];
+    for row in 0..height {
+        for col in 0..width {
+            let index = row * width + col;
+            let color = raw_image.cfa.color_at(row, col);
+            let black = blacklevels[color] as f32;
+            let white = whitelevels[color] as f32;
+            normalized[index] = ((samples[index] - black) / (white - black)).max(0.0).min(1.0);
+        }
+    }
+
+    // crop off the optical-black/masked borders and average each 2x2 CFA
+    // quad (one red, two green, one blue site) into a single luminance sample
+    let (top, right, bottom, left) = (raw_image.crops[0], raw_image.crops[1], raw_image.crops[2], raw_image.crops[3]);
+    let active_width = width.saturating_sub(left + right);
+    let active_height = height.saturating_sub(top + bottom);
+    let quad_width = active_width / 2;
+    let quad_height = active_height / 2;
+
+    if quad_width == 0 || quad_height == 0 {
+        println!("[RAW active area too small to decode] {}", path.display());
+        return None;
+    }
+
+    let mut pixels = Vec::with_capacity(quad_width * quad_height);
+    for qy in 0..quad_height {
+        for qx in 0..quad_width {
+            let row = top + qy * 2;
+            let col = left + qx * 2;
+            let mut sum = 0.0f32;
+            for dy in 0..2 {
+                for dx in 0..2 {
+                    sum += normalized[(row + dy) * width + (col + dx)];
+                }
+            }
+            pixels.push(((sum / 4.0) * 255.0) as u8);
+        }
+    }
+
+    match image::GrayImage::from_raw(quad_width as u32, quad_height as u32, pixels) {
+        Some(buffer) => Some(image::DynamicImage::ImageLuma8(buffer)),
+        None => { println!("[RAW pixel buffer didn't match its own dimensions] {}", path.display()); None }
+    }
+}
+
+// a fixed-width bit vector backing variable-size perceptual hashes
+#[derive(Debug, Clone)]
+struct BitHash {
+    words: Vec<u64>,
+    bits: u32,
+}
+
+impl BitHash {
+    fn zeros(bits: u32) -> BitHash {
+        let word_count = ((bits as usize) + 63) / 64;
+        BitHash { words: vec![0u64; word_count], bits: bits }
+    }
+
+    fn set(&mut self, index: u32) {
+        let word = (index / 64) as usize;
+        let bit = index % 64;
+        self.words[word] |= 1u64 << bit;
+    }
+
+    fn distance(&self, other: &BitHash) -> u32 {
+        self.words.iter().zip(other.words.iter())
+            .map(|(a, b)| (a ^ b).count_ones())
+            .fold(0, |sum, ones| sum + ones)
+    }
+
+    // parses the hex encoding produced by `Display`, for loading cached hashes
+    fn from_hex(hex: &str) -> Option<BitHash> {
+        if hex.len() == 0 || hex.len() % 16 != 0 {
+            return None;
+        }
+
+        let mut words = Vec::with_capacity(hex.len() / 16);
+        for chunk in hex.as_bytes().chunks(16) {
+            let word = match ::std::str::from_utf8(chunk).ok().and_then(|s| u64::from_str_radix(s, 16).ok()) {
+                Some(word) => word,
+                None => { return None; }
+            };
+            words.push(word);
+        }
+
+        let bits = (words.len() * 64) as u32;
+        Some(BitHash { words: words, bits: bits })
+    }
+}
+
+impl fmt::Display for BitHash {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        for word in self.words.iter() {
+            try!(write!(formatter, "{:016x}", word));
+        }
+        Ok(())
+    }
+}
+
+pub trait ImageSignature: fmt::Display + Send {
+    fn new(image: &image::DynamicImage, hash_size: u32) -> Self;
+    // reconstructs a signature from its `Display` hex encoding, for cache hits
+    fn from_hex(hex: &str) -> Option<Self>;
+
+    fn distance(&self, other: &Self) -> u32;
+    // total number of bits in the signature, for normalizing distance/threshold
+    fn bits(&self) -> u32;
+
+    // whether a computed hamming distance falls within the given threshold
+    fn is_similar(distance: u32, threshold: u32) -> bool {
+        distance <= threshold
+    }
+    // for human-interpretable measurements of similarity
+    fn similarity(&self, other: &Self) -> f64 {
+        1.0 - (self.distance(other) as f64 / self.bits() as f64)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PHash(BitHash);
+
+impl fmt::Display for PHash {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &PHash(ref bits) => { bits.fmt(formatter) }
+        }
+    }
+}
+
+impl ImageSignature for PHash {
+    fn new(image: &image::DynamicImage, hash_size: u32) -> PHash {
+        let n = hash_size as usize;
+        // Grayscale and resize image to 4n x 4n
+        let side = 4 * n;
+        let resized = image.grayscale().resize_exact(side as u32, side as u32, image::FilterType::Nearest);
+
+        // Compute top-left n x n of discrete cosine transform
+        // indexed by (i from 0 to side-1, k from 0 to n-1)
+        let mut cosines = vec![0.0f32; side * n];
+        for i in 0..side {
+            for k in 0..n {
+                cosines[n * i + k] = f32::cos(f32::consts::PI / side as f32 * (i as f32 + 0.5) * k as f32);
+            }
+        }
+
+        // compute transform terms
+        let mut transformed = vec![0.0f32; n * n];
+        for k1 in 0..n {
+            for k2 in 0..n {
+                let mut sum = 0.0f32;
+                for n1 in 0..side {
+                    for n2 in 0..side {
+                        match resized.get_pixel(n1 as u32, n2 as u32).channels4() {
+                            (r, _, _, _) => {
+                                sum += cosines[n * n1 + k1] * cosines[n * n2 + k2] * (r as f32 - 128.0);
+                            }
+                        }
+                    }
+                }
+                transformed[n * k1 + k2] = sum;
+            }
+        }
+
+        // Compute average value, excluding DC factor at (0, 0)
+        let mut average = 0.0f32;
+        for i in 1..(n * n) {
+            average += transformed[i] / ((n * n - 1) as f32);
+        }
+
+        // Compare each coefficient to the average value
+        let mut hash = BitHash::zeros((n * n) as u32);
+        for i in 0..(n * n) {
+            if transformed[i] >= average {
+                hash.set(i as u32);
+            }
+        }
+        PHash(hash)
+    }
+
+    fn from_hex(hex: &str) -> Option<PHash> {
+        BitHash::from_hex(hex).map(PHash)
+    }
+
+    fn distance(&self, other: &PHash) -> u32 {
+        // metric: hamming distance of two hashes
+        match (self, other) {
+            (&PHash(ref h1), &PHash(ref h2)) => {
+                h1.distance(h2)
+            }
+        }
+    }
+
+    fn bits(&self) -> u32 {
+        match self { &PHash(ref h) => h.bits }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AHash(BitHash);
+
+impl fmt::Display for AHash {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &AHash(ref bits) => { bits.fmt(formatter) }
+        }
+    }
+}
+
+impl ImageSignature for AHash {
+    fn new(image: &image::DynamicImage, hash_size: u32) -> AHash {
+        let n = hash_size;
+        // Grayscale and shrink to n x n
+        let resized = image.grayscale().resize_exact(n, n, image::FilterType::Nearest);
+
+        // Compute the mean pixel value
+        let mut sum = 0u32;
+        for y in 0..n {
+            for x in 0..n {
+                match resized.get_pixel(x, y).channels4() {
+                    (r, _, _, _) => { sum += r as u32; }
+                }
+            }
+        }
+        let mean = sum as f32 / (n * n) as f32;
+
+        // Set each bit by comparing the pixel to the mean
+        let mut hash = BitHash::zeros(n * n);
+        for y in 0..n {
+            for x in 0..n {
+                match resized.get_pixel(x, y).channels4() {
+                    (r, _, _, _) => {
+                        if r as f32 >= mean {
+                            hash.set(n * y + x);
+                        }
+                    }
+                }
+            }
+        }
+        AHash(hash)
+    }
+
+    fn from_hex(hex: &str) -> Option<AHash> {
+        BitHash::from_hex(hex).map(AHash)
+    }
+
+    fn distance(&self, other: &AHash) -> u32 {
+        match (self, other) {
+            (&AHash(ref h1), &AHash(ref h2)) => {
+                h1.distance(h2)
+            }
+        }
+    }
+
+    fn bits(&self) -> u32 {
+        match self { &AHash(ref h) => h.bits }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DHash(BitHash);
+
+impl fmt::Display for DHash {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &DHash(ref bits) => { bits.fmt(formatter) }
+        }
+    }
+}
+
+impl ImageSignature for DHash {
+    fn new(image: &image::DynamicImage, hash_size: u32) -> DHash {
+        let n = hash_size;
+        // Grayscale and shrink to (n+1) x n, so each row yields n adjacent-pixel comparisons
+        let resized = image.grayscale().resize_exact(n + 1, n, image::FilterType::Nearest);
+
+        // Set each bit by comparing adjacent horizontal pixels
+        let mut hash = BitHash::zeros(n * n);
+        for y in 0..n {
+            for x in 0..n {
+                let left = resized.get_pixel(x, y).channels4().0;
+                let right = resized.get_pixel(x + 1, y).channels4().0;
+                if left >= right {
+                    hash.set(n * y + x);
+                }
+            }
+        }
+        DHash(hash)
+    }
+
+    fn from_hex(hex: &str) -> Option<DHash> {
+        BitHash::from_hex(hex).map(DHash)
+    }
+
+    fn distance(&self, other: &DHash) -> u32 {
+        match (self, other) {
+            (&DHash(ref h1), &DHash(ref h2)) => {
+                h1.distance(h2)
+            }
+        }
+    }
+
+    fn bits(&self) -> u32 {
+        match self { &DHash(ref h) => h.bits }
+    }
+}
+
+#[derive(Clone)]
+pub struct ProcessedImage<T: ImageSignature> {
+  pub sig: T,
+  pub path: PathBuf,
+  pub size: u64,
+}
+
+// a BK-tree over hamming distance: each node stores one signature (by index
+// into an external slice), and its children are keyed by their integer
+// distance from the node. Querying within a threshold only has to recurse
+// into child edges in [d - threshold, d + threshold], pruning most of the
+// tree by the triangle inequality.
+struct BKNode {
+  index: usize,
+  children: HashMap<u32, BKNode>,
+}
+
+impl BKNode {
+  fn insert<T: ImageSignature>(&mut self, images: &[ProcessedImage<T>], index: usize) {
+    let d = images[self.index].sig.distance(&images[index].sig);
+    match self.children.get_mut(&d) {
+      Some(child) => { return child.insert(images, index); },
+      None => {}
+    }
+    self.children.insert(d, BKNode { index: index, children: HashMap::new() });
+  }
+
+  // appends the indices of every node within `threshold` of `images[target]`
+  fn query<T: ImageSignature>(&self, images: &[ProcessedImage<T>], target: usize, threshold: u32, out: &mut Vec<usize>) {
+    let d = images[self.index].sig.distance(&images[target].sig);
+    if T::is_similar(d, threshold) {
+      out.push(self.index);
+    }
+    let lo = d.saturating_sub(threshold);
+    let hi = d + threshold;
+    for (&edge, child) in self.children.iter() {
+      if edge >= lo && edge <= hi {
+        child.query(images, target, threshold, out);
+      }
+    }
+  }
+
+  // like `query`, but against a signature that isn't itself a member of `images`
+  fn query_signature<T: ImageSignature>(&self, images: &[ProcessedImage<T>], target: &T, threshold: u32, out: &mut Vec<usize>) {
+    let d = images[self.index].sig.distance(target);
+    if T::is_similar(d, threshold) {
+      out.push(self.index);
+    }
+    let lo = d.saturating_sub(threshold);
+    let hi = d + threshold;
+    for (&edge, child) in self.children.iter() {
+      if edge >= lo && edge <= hi {
+        child.query_signature(images, target, threshold, out);
+      }
+    }
+  }
+}
+
+struct BKTree {
+  root: Option<BKNode>,
+}
+
+impl BKTree {
+  fn new() -> BKTree {
+    BKTree { root: None }
+  }
+
+  fn insert<T: ImageSignature>(&mut self, images: &[ProcessedImage<T>], index: usize) {
+    match self.root {
+      Some(ref mut root) => { return root.insert(images, index); },
+      None => {}
+    }
+    self.root = Some(BKNode { index: index, children: HashMap::new() });
+  }
+
+  fn query<T: ImageSignature>(&self, images: &[ProcessedImage<T>], target: usize, threshold: u32, out: &mut Vec<usize>) {
+    if let Some(ref root) = self.root {
+      root.query(images, target, threshold, out);
+    }
+  }
+
+  fn query_signature<T: ImageSignature>(&self, images: &[ProcessedImage<T>], target: &T, threshold: u32, out: &mut Vec<usize>) {
+    if let Some(ref root) = self.root {
+      root.query_signature(images, target, threshold, out);
+    }
+  }
+}
+
+// maps a --similarity level to a hamming-distance threshold for a given bit count.
+// base thresholds are calibrated for 64-bit hashes; scale proportionally so
+// larger hashes keep the same relative cutoff.
+pub fn similarity_threshold(level: &str, bits: u32) -> u32 {
+  let base: u32 = match level {
+    "minimal" => 14,
+    "small" => 7,
+    "medium" => 5,
+    "high" => 2,
+    "very-high" => 1,
+    other => {
+      println!("Unknown similarity level '{}', falling back to 'medium'", other);
+      5
+    }
+  };
+  ((base as u64 * bits as u64) / 64) as u32
+}
+
+// clusters already-hashed images: for each unassigned image, query its
+// neighbors within the similarity threshold and claim them as one group.
+pub fn group_by_threshold<T: ImageSignature>(images: Vec<ProcessedImage<T>>, threshold: u32) -> Vec<Vec<ProcessedImage<T>>> {
+  let mut tree = BKTree::new();
+  for i in 0..images.len() {
+    tree.insert(&images, i);
+  }
+
+  let mut assigned = vec![false; images.len()];
+  let mut groups: Vec<Vec<ProcessedImage<T>>> = Vec::new();
+
+  for i in 0..images.len() {
+    if assigned[i] { continue }
+
+    let mut neighbors = Vec::new();
+    tree.query(&images, i, threshold, &mut neighbors);
+
+    let mut group = Vec::new();
+    for &j in neighbors.iter() {
+      if !assigned[j] {
+        assigned[j] = true;
+        group.push(images[j].clone());
+      }
+    }
+    groups.push(group);
+  }
+
+  groups
+}
+
+// matches every source image against a fixed, canonical reference pool: each
+// source image is paired with its nearest reference neighbor within
+// `threshold` (if any). Source images with no reference match are returned
+// separately so the caller can group those among themselves.
+pub fn match_against_reference<T: ImageSignature>(
+      source_images: Vec<ProcessedImage<T>>,
+      reference_images: &[ProcessedImage<T>],
+      threshold: u32,
+  ) -> (Vec<(usize, ProcessedImage<T>)>, Vec<ProcessedImage<T>>) {
+  let mut tree = BKTree::new();
+  for i in 0..reference_images.len() {
+    tree.insert(reference_images, i);
+  }
+
+  let mut matched = Vec::new();
+  let mut unmatched = Vec::new();
+
+  for image in source_images {
+    let mut neighbors = Vec::new();
+    tree.query_signature(reference_images, &image.sig, threshold, &mut neighbors);
+
+    let closest = neighbors.into_iter().min_by_key(|&idx| reference_images[idx].sig.distance(&image.sig));
+    match closest {
+      Some(idx) => { matched.push((idx, image)); },
+      None => { unmatched.push(image); }
+    }
+  }
+
+  (matched, unmatched)
+}
+
+// the hash size used by the plain library API, where no --hash-size flag applies
+pub const DEFAULT_HASH_SIZE: u32 = 8;
+
+// decodes a file (native or external format) and computes its signature,
+// without any of the binary's caching or threading machinery
+fn decode_and_hash<T: ImageSignature>(path: &Path, hash_size: u32) -> Option<(T, u64)> {
+  let format = match supported_extension(path) {
+    Some(format) => format,
+    None => { return None; }
+  };
+
+  let decoded = match format {
+    DecodeFormat::Native(native_format) => {
+      fs::File::open(path).ok().and_then(|file| {
+        match image::load(file, native_format) {
+          Err(err) => { println!("[{}] {}", err, path.display()); None },
+          Ok(image) => { Some(image) }
+        }
+      })
+    },
+    DecodeFormat::Heic => { decode_heic(path) },
+    DecodeFormat::Raw => { decode_raw(path) },
+  };
+
+  decoded.map(|image| {
+    let sig: T = ImageSignature::new(&image, hash_size);
+    let pixel_size = (image.width() as u64) * (image.height() as u64);
+    (sig, pixel_size)
+  })
+}
+
+// hashes a single image file with the default hash size, for embedding in
+// other programs that just want a signature without the CLI's pipeline
+pub fn hash_image<T: ImageSignature>(path: &Path) -> Option<T> {
+  decode_and_hash(path, DEFAULT_HASH_SIZE).map(|(sig, _)| sig)
+}
+
+// hashes every path and groups the ones within `threshold` of each other,
+// without touching the filesystem beyond reading the images themselves
+pub fn find_similar_groups<T: ImageSignature>(paths: &[PathBuf], threshold: u32) -> Vec<Vec<ProcessedImage<T>>> {
+  let mut processed_images = Vec::new();
+  for path in paths {
+    if let Some((sig, pixel_size)) = decode_and_hash(path, DEFAULT_HASH_SIZE) {
+      processed_images.push(ProcessedImage { sig: sig, path: path.clone(), size: pixel_size });
+    }
+  }
+
+  group_by_threshold(processed_images, threshold)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn bithash_hex_round_trips() {
+    let mut hash = BitHash::zeros(128);
+    hash.set(0);
+    hash.set(5);
+    hash.set(63);
+    hash.set(127);
+
+    let hex = format!("{}", hash);
+    let parsed = BitHash::from_hex(&hex).unwrap();
+    assert_eq!(parsed.words, hash.words);
+    assert_eq!(parsed.bits, hash.bits);
+  }
+
+  #[test]
+  fn bithash_from_hex_rejects_malformed_input() {
+    assert!(BitHash::from_hex("").is_none());
+    assert!(BitHash::from_hex("abc").is_none());
+    assert!(BitHash::from_hex("zzzzzzzzzzzzzzzz").is_none());
+  }
+
+  fn fake_image(path: &str, bits: &[u32]) -> ProcessedImage<PHash> {
+    let mut hash = BitHash::zeros(64);
+    for &bit in bits {
+      hash.set(bit);
+    }
+    ProcessedImage { sig: PHash(hash), path: PathBuf::from(path), size: 0 }
+  }
+
+  fn bruteforce_neighbors<T: ImageSignature>(images: &[ProcessedImage<T>], target: usize, threshold: u32) -> Vec<usize> {
+    let mut out: Vec<usize> = (0..images.len())
+      .filter(|&i| T::is_similar(images[i].sig.distance(&images[target].sig), threshold))
+      .collect();
+    out.sort();
+    out
+  }
+
+  #[test]
+  fn bktree_query_matches_bruteforce() {
+    let images: Vec<ProcessedImage<PHash>> = vec![
+      fake_image("a", &[]),
+      fake_image("b", &[0]),
+      fake_image("c", &[0, 1]),
+      fake_image("d", &[0, 1, 2]),
+      fake_image("e", &[10, 20, 30, 40]),
+      fake_image("f", &[10, 20, 30, 41]),
+      fake_image("g", &[63]),
+    ];
+
+    let mut tree = BKTree::new();
+    for i in 0..images.len() {
+      tree.insert(&images, i);
+    }
+
+    for threshold in 0..4 {
+      for target in 0..images.len() {
+        let mut via_tree = Vec::new();
+        tree.query(&images, target, threshold, &mut via_tree);
+        via_tree.sort();
+        assert_eq!(via_tree, bruteforce_neighbors(&images, target, threshold));
+      }
+    }
+  }
+
+  #[test]
+  fn group_by_threshold_matches_bruteforce_clustering() {
+    let images: Vec<ProcessedImage<PHash>> = vec![
+      fake_image("a", &[]),
+      fake_image("b", &[0]),
+      fake_image("c", &[10, 20, 30, 40]),
+      fake_image("d", &[10, 20, 30, 41]),
+    ];
+
+    let groups = group_by_threshold(images, 1);
+    let mut sizes: Vec<usize> = groups.iter().map(|g| g.len()).collect();
+    sizes.sort();
+    assert_eq!(sizes, vec![2, 2]);
+  }
+}
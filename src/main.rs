@@ -2,161 +2,220 @@ extern crate image;
 extern crate rustc_serialize;
 extern crate docopt;
 extern crate glob;
+extern crate flate2;
+extern crate rayon;
+extern crate superdeduper;
 
 use docopt::Docopt;
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use rayon::prelude::*;
 use std::fs;
-use std::fmt;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
 use std::ascii::AsciiExt;
-use std::f32;
-use image::GenericImage;
-use image::Pixel;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::UNIX_EPOCH;
+use superdeduper::{DecodeFormat, ImageSignature, ProcessedImage, PHash, AHash, DHash};
+use superdeduper::{supported_extension, decode_heic, decode_raw, similarity_threshold, group_by_threshold, match_against_reference};
 
 static USAGE: &'static str = "
-Image deduplicator. Implemented using the pHash perceptual hash algorithm.
+Image deduplicator. Implemented using selectable perceptual hash algorithms.
 This program moves all images from source to target, renaming similar images
 as <canonical hash>-<dupe number>-<image hash> for easy recognition and deletion.
 
-Usage: superdeduper <source> <target>
+Usage: superdeduper [--algorithm=<alg>] [--hash-size=<n>] [--similarity=<level>] [--no-cache] [--threads=<n>] [--reference=<dir>] <source> <target>
+
+Options:
+    --algorithm=<alg>     Hash algorithm to use: phash, ahash, or dhash. [default: phash]
+    --hash-size=<n>       Hash dimension per side, giving n*n-bit signatures: 8, 16, or 32. [default: 8]
+    --similarity=<level>  Similarity threshold: minimal, small, medium, high, or very-high. [default: medium]
+    --no-cache            Recompute every signature instead of reading/writing the on-disk cache.
+    --threads=<n>         Worker threads for reading and hashing images, 0 = all cores. [default: 0]
+    --reference=<dir>     Treat <dir> as a canonical, never-moved reference: <source> images matching
+                          one of its images are reported/renamed as its duplicate, and only
+                          non-matching <source> images are grouped among themselves. [default: ]
 ";
 
 #[derive(RustcDecodable, Debug)]
 struct Args {
     arg_source: String,
     arg_target: String,
+    flag_algorithm: String,
+    flag_hash_size: String,
+    flag_similarity: String,
+    flag_no_cache: bool,
+    flag_threads: String,
+    flag_reference: String,
 }
 
-// extension-based detection of filetype
-fn supported_extension(path: &Path) -> Option<image::ImageFormat> {
-    match path.extension() {
-        None => { None },
-        Some(ext) => {
-            match ext.to_str().unwrap().to_ascii_lowercase().as_ref() {
-                "gif" => { Some(image::ImageFormat::GIF) },
-                "png" => { Some(image::ImageFormat::PNG) },
-                "png-large" => { Some(image::ImageFormat::PNG) },
-                "jpg" => { Some(image::ImageFormat::JPEG) },
-                "jpeg" => { Some(image::ImageFormat::JPEG) },
-                "jpe" => { Some(image::ImageFormat::JPEG) },
-                "jpg-large" => { Some(image::ImageFormat::JPEG) },
-                "webp" => { Some(image::ImageFormat::WEBP) },
-                _ => { None },
+// an on-disk, zlib-compressed, line-oriented cache of (path, size, mtime) ->
+// signature, keyed by file path, so unchanged files skip recomputing their
+// signature. `version` stamps the algorithm and hash size the cache was built
+// with; a mismatch discards the whole cache instead of returning stale hashes.
+struct HashCache {
+  path: PathBuf,
+  version: String,
+  entries: HashMap<String, (u64, u64, u64, String)>,
+  dirty: bool,
+  enabled: bool,
+}
+
+impl HashCache {
+  fn load(source: &str, version: &str, enabled: bool) -> HashCache {
+    let cache_path = Path::new(source).join(".superdeduper_cache").join("hashes.zlib");
+    let mut entries = HashMap::new();
+
+    if enabled {
+      if let Ok(file) = fs::File::open(&cache_path) {
+        let mut decoder = ZlibDecoder::new(file);
+        let mut contents = String::new();
+        if decoder.read_to_string(&mut contents).is_ok() {
+          let mut lines = contents.lines();
+          if lines.next() == Some(version) {
+            for line in lines {
+              let fields: Vec<&str> = line.splitn(5, '\t').collect();
+              if fields.len() == 5 {
+                if let (Ok(file_size), Ok(mtime), Ok(pixel_size)) =
+                    (fields[1].parse(), fields[2].parse(), fields[3].parse()) {
+                  entries.insert(fields[0].to_string(), (file_size, mtime, pixel_size, fields[4].to_string()));
+                }
+              }
             }
+          } else {
+            println!("[Cache was built with a different algorithm/hash-size, discarding it.]");
+          }
         }
+      }
     }
-}
-
-trait ImageSignature: fmt::Display {
-    fn new(image: &image::DynamicImage) -> Self;
 
-    fn distance(&self, other: &Self) -> u32;
-    fn is_similar(distance: u32) -> bool;
-    // for human-interpretable measurements of similarity
-    fn similarity(&self, other: &Self) -> f64;
-}
+    HashCache {
+      path: cache_path,
+      version: version.to_string(),
+      entries: entries,
+      dirty: false,
+      enabled: enabled,
+    }
+  }
 
-#[derive(Debug)]
-struct PHash(u64);
+  // returns (pixel count, hex signature) if this path's size and mtime still match
+  fn get(&self, path: &Path, file_size: u64, mtime: u64) -> Option<(u64, String)> {
+    path.to_str().and_then(|key| self.entries.get(key)).and_then(|&(cached_size, cached_mtime, pixel_size, ref hex)| {
+      if cached_size == file_size && cached_mtime == mtime {
+        Some((pixel_size, hex.clone()))
+      } else {
+        None
+      }
+    })
+  }
 
-impl fmt::Display for PHash {
-    fn fmt(&self, formatter: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        match self {
-            &PHash(repr) => { write!(formatter, "{:016x}", repr) }
-        }
+  fn put(&mut self, path: &Path, file_size: u64, mtime: u64, pixel_size: u64, hex: String) {
+    if !self.enabled {
+      return;
     }
-}
-
-impl ImageSignature for PHash {
-    fn new(image: &image::DynamicImage) -> PHash {
-        // Grayscale and resize image to 32x32
-        let resized = image.grayscale().resize_exact(32, 32, image::FilterType::Nearest);
-        // Compute top-left 8x8 of discrete cosine transform
-        // indexed by (n from 0 to 31, k from 0 to 7)
-        let mut cosines: [f32; 256] = [0.0; 256];
-        let mut transformed: [f32; 64] = [0.0; 64];
-        // compute cosine terms
-        for i in 0..32 {
-            for j in 0..8 {
-                cosines[8 * i + j] = f32::cos(f32::consts::PI / 32.0 * (i as f32 + 0.5) * j as f32); 
-            }
-        }
-        // compute transform terms
-        for k1 in 0..8 {
-            for k2 in 0..8 {
-                for n1 in 0..32 {
-                    for n2 in 0..32 {
-                        match resized.get_pixel(n1 as u32, n2 as u32).channels4() {
-                            (r, _, _, _) => {
-                                transformed[8 * k1 + k2] += cosines[8 * n1 + k1] * cosines[8 * n2 + k2] * (r as f32 - 128.0);
-                            }
-                        }
-                    }
-                }
-            }
-        }
-        // Compute average value, excluding DC factor at (0, 0)
-        let mut average = 0.0f32;
-        for i in 1..64 {
-            average += transformed[i] / 63.0;
-        }
+    if let Some(key) = path.to_str() {
+      self.entries.insert(key.to_string(), (file_size, mtime, pixel_size, hex));
+      self.dirty = true;
+    }
+  }
 
-        // Compare each pixel to average value
-        let mut hash_value = 0u64;
-        for i in 0..64 {
-            if transformed[i] >= average {
-                hash_value |= 1 << i;
-            }
-        }
-        PHash(hash_value)
+  fn save(&self) {
+    if !self.enabled || !self.dirty {
+      return;
     }
 
-    fn distance(&self, other: &PHash) -> u32 {
-        // metric: hamming distance of two hashes
-        match (self, other) {
-            (&PHash(h1), &PHash(h2)) => {
-                (h1 ^ h2).count_ones()
-            }
-        }
+    if let Some(parent) = self.path.parent() {
+      match fs::create_dir_all(parent) {
+        Err(err) => { println!("{}", err); return; },
+        Ok(_) => {}
+      }
     }
 
-    fn is_similar(distance: u32) -> bool {
-        distance < 8
+    let file = match fs::File::create(&self.path) {
+      Err(err) => { println!("{}", err); return; },
+      Ok(file) => file,
+    };
+
+    let mut contents = String::new();
+    contents.push_str(&self.version);
+    contents.push('\n');
+    for (path, &(file_size, mtime, pixel_size, ref hex)) in self.entries.iter() {
+      contents.push_str(&format!("{}\t{}\t{}\t{}\t{}\n", path, file_size, mtime, pixel_size, hex));
     }
 
-    fn similarity(&self, other: &PHash) -> f64 {
-        1.0 - (self.distance(other) as f64 / 64.0)
+    let mut encoder = ZlibEncoder::new(file, Compression::default());
+    match encoder.write_all(contents.as_bytes()) {
+      Err(err) => { println!("{}", err); },
+      Ok(_) => {}
     }
+  }
 }
 
-#[derive(Clone)]
-struct ProcessedImage<T: ImageSignature> {
-  sig: T,
+// a freshly-computed signature that still needs to be written back to the cache
+struct NewCacheEntry {
   path: PathBuf,
-  size: u64,
+  file_size: u64,
+  mtime: u64,
+  pixel_size: u64,
+  hex: String,
 }
 
-// read files and generate signatures for them
+// read a file and compute its signature, reusing a cache hit if one applies.
+// takes a shared reference so this can run from multiple worker threads at
+// once; any freshly-computed signature is handed back for the caller to
+// store in the cache once the parallel phase is done.
 fn process_image<T: ImageSignature>(
       pathbuf: PathBuf,
-      format: image::ImageFormat
-  ) -> Option<ProcessedImage<T>> {
-  fs::File::open(pathbuf.as_path()).ok().and_then(|file| {
-      let im = image::load(file, format);
-
-      match im {
-        Err(err) => {
-          // image could not be read by image library
-          println!("[{}] {}", err, pathbuf.display());
-          None
-        },
-        Ok(image) => { Some(image) }
-      }
-    }).map(|image| {
-      ProcessedImage {
-        sig: ImageSignature::new(&image),
-        path: pathbuf,
-        size: (image.width() as u64) * (image.height() as u64)
-      }
+      format: DecodeFormat,
+      hash_size: u32,
+      cache: &HashCache,
+  ) -> Option<(ProcessedImage<T>, Option<NewCacheEntry>)> {
+  let metadata = match fs::metadata(pathbuf.as_path()) {
+    Ok(metadata) => metadata,
+    Err(err) => { println!("[{}] {}", err, pathbuf.display()); return None; }
+  };
+  let file_size = metadata.len();
+  let mtime = metadata.modified().ok()
+    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+    .map(|duration| duration.as_secs())
+    .unwrap_or(0);
+
+  if let Some((pixel_size, hex)) = cache.get(pathbuf.as_path(), file_size, mtime) {
+    if let Some(sig) = T::from_hex(&hex) {
+      return Some((ProcessedImage { sig: sig, path: pathbuf, size: pixel_size }, None));
+    }
+  }
+
+  let decoded = match format {
+    DecodeFormat::Native(native_format) => {
+      fs::File::open(pathbuf.as_path()).ok().and_then(|file| {
+        match image::load(file, native_format) {
+          Err(err) => {
+            // image could not be read by image library
+            println!("[{}] {}", err, pathbuf.display());
+            None
+          },
+          Ok(image) => { Some(image) }
+        }
+      })
+    },
+    DecodeFormat::Heic => { decode_heic(pathbuf.as_path()) },
+    DecodeFormat::Raw => { decode_raw(pathbuf.as_path()) },
+  };
+
+  decoded.map(|image| {
+      let sig: T = ImageSignature::new(&image, hash_size);
+      let pixel_size = (image.width() as u64) * (image.height() as u64);
+      let new_entry = NewCacheEntry {
+        path: pathbuf.clone(),
+        file_size: file_size,
+        mtime: mtime,
+        pixel_size: pixel_size,
+        hex: sig.to_string(),
+      };
+      (ProcessedImage { sig: sig, path: pathbuf, size: pixel_size }, Some(new_entry))
   })
 }
 
@@ -178,67 +237,97 @@ fn new_filename<T: ImageSignature>(
   }
 
   match supported_extension(old_path) {
-    Some(image::ImageFormat::GIF) => { new_path.set_extension("gif"); },
-    Some(image::ImageFormat::PNG) => { new_path.set_extension("png"); },
-    Some(image::ImageFormat::JPEG) => { new_path.set_extension("jpg"); },
-    Some(image::ImageFormat::WEBP) => { new_path.set_extension("webp"); },
-    _ => {}
+    Some(DecodeFormat::Native(image::ImageFormat::GIF)) => { new_path.set_extension("gif"); },
+    Some(DecodeFormat::Native(image::ImageFormat::PNG)) => { new_path.set_extension("png"); },
+    Some(DecodeFormat::Native(image::ImageFormat::JPEG)) => { new_path.set_extension("jpg"); },
+    Some(DecodeFormat::Native(image::ImageFormat::WEBP)) => { new_path.set_extension("webp"); },
+    Some(DecodeFormat::Native(_)) => {},
+    Some(DecodeFormat::Heic) | Some(DecodeFormat::Raw) => {
+      // HEIC/RAW originals are renamed in place, not re-encoded; keep their extension
+      if let Some(ext) = old_path.extension() {
+        new_path.set_extension(ext);
+      }
+    },
+    None => {}
   }
 
   new_path
 }
 
-fn main() {
-  let args: Args = Docopt::new(USAGE)
-                          .and_then(|d| d.decode())
-                          .unwrap_or_else(|e| e.exit());
-
-  // container for image metadata and signatures
+// reads and hashes every image directly inside `source`, using (and updating)
+// its on-disk cache, spread across a work-stealing thread pool
+fn read_images<T: ImageSignature>(source: &str, hash_size: u32, algorithm: &str, use_cache: bool, threads: usize) -> Vec<ProcessedImage<T>> {
   let mut processed_images = Vec::new();
-  let new_directory = Path::new(&args.arg_target);
 
-  // inline renaming not implemented, don't be destructive
-  assert!(args.arg_source != args.arg_target);
-
-  println!("[Reading images.]");
-  // for each path in the directory
-  for glob_result in glob::glob(&(args.arg_source + "/*")).unwrap() {
-      let pathbuf: PathBuf = glob_result.unwrap();
-      if fs::metadata(pathbuf.as_path()).unwrap().is_file() {
-          supported_extension(pathbuf.as_path()).and_then(|format| {
-              // create the image signature
-              process_image::<PHash>(pathbuf, format)
-          }).map(|processed_image| {
-              println!("{} {}", processed_image.sig, processed_image.path.display());
-              processed_images.push(processed_image);
-          });
+  let cache_version = format!("v1:{}:{}", algorithm, hash_size);
+  let mut cache = HashCache::load(source, &cache_version, use_cache);
+
+  let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+
+  // gather the candidate paths first so the read/hash stage can be spread
+  // across a work-stealing thread pool instead of processed one at a time
+  let image_paths: Vec<PathBuf> = glob::glob(&(source.to_string() + "/*")).unwrap()
+    .map(|glob_result| glob_result.unwrap())
+    .filter(|pathbuf| fs::metadata(pathbuf.as_path()).unwrap().is_file())
+    .collect();
+
+  let total = image_paths.len();
+  let processed_count = AtomicUsize::new(0);
+
+  let results: Vec<Option<(ProcessedImage<T>, Option<NewCacheEntry>)>> = pool.install(|| {
+    image_paths.into_par_iter().map(|pathbuf| {
+      let result = supported_extension(pathbuf.as_path()).and_then(|format| {
+        // create the image signature
+        process_image::<T>(pathbuf, format, hash_size, &cache)
+      });
+      let done = processed_count.fetch_add(1, Ordering::SeqCst) + 1;
+      print!("\r[{} / {} files processed]", done, total);
+      io::stdout().flush().ok();
+      result
+    }).collect()
+  });
+  println!("");
+
+  for result in results {
+    if let Some((processed_image, new_entry)) = result {
+      if let Some(entry) = new_entry {
+        cache.put(&entry.path, entry.file_size, entry.mtime, entry.pixel_size, entry.hex);
       }
+      processed_images.push(processed_image);
+    }
   }
   println!("[{} files read.]", processed_images.len());
+  cache.save();
 
-  println!("[Finding dupes. This might take a while!]");
-  let mut dupes: Vec<Vec<ProcessedImage<PHash>>> = Vec::new();
+  processed_images
+}
 
-  // get an image with largest resolution, find its neighbors until empty.
-  // not very rustic because my rust is very rusty :(
-  while !processed_images.is_empty() {
-    let mut neighbors = Vec::new();
-    let image = processed_images.pop().unwrap();
+// moves every image in `group` into `new_directory`, named after `canon`
+// (the group's canonical signature) with an incrementing dupe number,
+// starting at `start_version` (0 if the canonical image itself is part of
+// the group and being moved too, 1 if it's a reference image staying put)
+fn move_group<T: ImageSignature>(group: &[ProcessedImage<T>], canon: &T, new_directory: &Path, start_version: u32) {
+  for (i, image) in group.iter().enumerate() {
+    let new_loc = new_filename(&image.path, new_directory, canon, &image.sig, start_version + i as u32);
+    println!("{} => {}", image.path.display(), new_loc.display());
+    match fs::rename(&image.path, &new_loc) {
+      Err(err) => { println!("{}", err); },
+      Ok(_) => { }
+    }
+  }
+}
 
-    let mut i = processed_images.len();
+// runs the full read -> group -> move pipeline for a chosen signature type
+fn run<T: ImageSignature>(source: &str, target: &str, hash_size: u32, similarity: &str, algorithm: &str, use_cache: bool, threads: usize) {
+  let new_directory = Path::new(target);
 
-    loop {
-      if i == 0 { break }
-      i -= 1;
-      if PHash::is_similar(processed_images[i].sig.distance(&image.sig)) {
-        neighbors.push(processed_images.remove(i));
-      }
-    }
+  println!("[Reading images.]");
+  let processed_images = read_images::<T>(source, hash_size, algorithm, use_cache, threads);
 
-    neighbors.push(image);
-    dupes.push(neighbors);
-  }
+  let threshold = similarity_threshold(similarity, hash_size * hash_size);
 
+  println!("[Finding dupes. This might take a while!]");
+  let mut dupes = group_by_threshold(processed_images, threshold);
   dupes.sort_by(|a, b| { b.len().cmp(&a.len()) });
 
   println!("[Moving files.]");
@@ -251,14 +340,151 @@ fn main() {
 
     // canonical image is the one with the largest file size
     let canon = &group[group.len() - 1].sig;
-    for (i, image) in group.iter().enumerate() {
-      let new_loc = new_filename(&image.path, &new_directory, canon, &image.sig, i as u32);
-      println!("{} => {}", image.path.display(), new_loc.display());
-      match fs::rename(&image.path, &new_loc) {
-        Err(err) => { println!("{}", err); },
-        Ok(_) => { }
+    move_group(group, canon, &new_directory, 0);
+  }
+}
+
+// like `run`, but treats every image under `reference` as canonical: those
+// files are hashed for comparison but never moved. Source images matching a
+// reference image are renamed as its duplicate; source images with no
+// reference match are grouped (and canonicalized) among themselves as usual.
+fn run_with_reference<T: ImageSignature>(source: &str, target: &str, reference: &str, hash_size: u32, similarity: &str, algorithm: &str, use_cache: bool, threads: usize) {
+  let new_directory = Path::new(target);
+
+  println!("[Reading reference images.]");
+  let reference_images = read_images::<T>(reference, hash_size, algorithm, use_cache, threads);
+
+  println!("[Reading source images.]");
+  let source_images = read_images::<T>(source, hash_size, algorithm, use_cache, threads);
+
+  let threshold = similarity_threshold(similarity, hash_size * hash_size);
+
+  println!("[Matching against reference. This might take a while!]");
+  let (matched, unmatched) = match_against_reference(source_images, &reference_images, threshold);
+
+  println!("[Moving files.]");
+  match fs::create_dir_all(new_directory) {
+    Err(err) => { println!("{}", err); },
+    Ok(_) => { }
+  }
+
+  // gather each reference image's matches so dupe numbers increment per canonical
+  let mut groups_by_reference: HashMap<usize, Vec<ProcessedImage<T>>> = HashMap::new();
+  for (reference_index, image) in matched {
+    groups_by_reference.entry(reference_index).or_insert_with(Vec::new).push(image);
+  }
+  for (reference_index, group) in groups_by_reference.iter() {
+    // the reference image itself stays put, so dupe numbering starts at 1
+    let canon = &reference_images[*reference_index].sig;
+    move_group(group, canon, &new_directory, 1);
+  }
+
+  let mut own_dupes = group_by_threshold(unmatched, threshold);
+  own_dupes.sort_by(|a, b| { b.len().cmp(&a.len()) });
+  for group in own_dupes.iter() {
+    assert!(group.len() > 0);
+
+    // canonical image is the one with the largest file size
+    let canon = &group[group.len() - 1].sig;
+    move_group(group, canon, &new_directory, 0);
+  }
+}
+
+fn main() {
+  let args: Args = Docopt::new(USAGE)
+                          .and_then(|d| d.decode())
+                          .unwrap_or_else(|e| e.exit());
+
+  // inline renaming not implemented, don't be destructive
+  assert!(args.arg_source != args.arg_target);
+
+  let hash_size: u32 = match args.flag_hash_size.parse() {
+    Ok(8) => 8,
+    Ok(16) => 16,
+    Ok(32) => 32,
+    _ => {
+      println!("Unsupported --hash-size '{}', expected one of: 8, 16, 32", args.flag_hash_size);
+      std::process::exit(1);
+    }
+  };
+
+  let use_cache = !args.flag_no_cache;
+  let algorithm = args.flag_algorithm.to_ascii_lowercase();
+
+  let threads: usize = match args.flag_threads.parse() {
+    Ok(threads) => threads,
+    Err(_) => {
+      println!("Unsupported --threads '{}', expected a non-negative integer", args.flag_threads);
+      std::process::exit(1);
+    }
+  };
+
+  if args.flag_reference.is_empty() {
+    match algorithm.as_ref() {
+      "ahash" => { run::<AHash>(&args.arg_source, &args.arg_target, hash_size, &args.flag_similarity, &algorithm, use_cache, threads); },
+      "dhash" => { run::<DHash>(&args.arg_source, &args.arg_target, hash_size, &args.flag_similarity, &algorithm, use_cache, threads); },
+      "phash" => { run::<PHash>(&args.arg_source, &args.arg_target, hash_size, &args.flag_similarity, &algorithm, use_cache, threads); },
+      other => {
+        println!("Unknown algorithm '{}', expected one of: phash, ahash, dhash", other);
+        std::process::exit(1);
+      }
+    }
+  } else {
+    // inline renaming not implemented, don't be destructive
+    assert!(args.flag_reference != args.arg_target);
+
+    match algorithm.as_ref() {
+      "ahash" => { run_with_reference::<AHash>(&args.arg_source, &args.arg_target, &args.flag_reference, hash_size, &args.flag_similarity, &algorithm, use_cache, threads); },
+      "dhash" => { run_with_reference::<DHash>(&args.arg_source, &args.arg_target, &args.flag_reference, hash_size, &args.flag_similarity, &algorithm, use_cache, threads); },
+      "phash" => { run_with_reference::<PHash>(&args.arg_source, &args.arg_target, &args.flag_reference, hash_size, &args.flag_similarity, &algorithm, use_cache, threads); },
+      other => {
+        println!("Unknown algorithm '{}', expected one of: phash, ahash, dhash", other);
+        std::process::exit(1);
       }
     }
   }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
 
+  fn temp_cache_dir(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("superdeduper_test_{}_{}", name, std::process::id()))
+  }
+
+  #[test]
+  fn hash_cache_round_trips_through_disk() {
+    let dir = temp_cache_dir("round_trip");
+    fs::create_dir_all(&dir).unwrap();
+
+    {
+      let mut cache = HashCache::load(dir.to_str().unwrap(), "v1:phash:8", true);
+      cache.put(Path::new("photo.jpg"), 1234, 5678, 10000, "deadbeefdeadbeef".to_string());
+      cache.save();
+    }
+
+    let cache = HashCache::load(dir.to_str().unwrap(), "v1:phash:8", true);
+    assert_eq!(cache.get(Path::new("photo.jpg"), 1234, 5678), Some((10000, "deadbeefdeadbeef".to_string())));
+    assert_eq!(cache.get(Path::new("photo.jpg"), 1234, 9999), None);
+
+    fs::remove_dir_all(&dir).ok();
+  }
+
+  #[test]
+  fn hash_cache_discards_on_version_mismatch() {
+    let dir = temp_cache_dir("version_mismatch");
+    fs::create_dir_all(&dir).unwrap();
+
+    {
+      let mut cache = HashCache::load(dir.to_str().unwrap(), "v1:phash:8", true);
+      cache.put(Path::new("photo.jpg"), 1234, 5678, 10000, "deadbeefdeadbeef".to_string());
+      cache.save();
+    }
+
+    let cache = HashCache::load(dir.to_str().unwrap(), "v1:phash:16", true);
+    assert_eq!(cache.get(Path::new("photo.jpg"), 1234, 5678), None);
+
+    fs::remove_dir_all(&dir).ok();
+  }
 }